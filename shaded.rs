@@ -1,24 +1,96 @@
 extern crate gpio_cdev;
-extern crate bufstream;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io;
-use std::io::Write;
-use std::io::BufRead;
-use bufstream::BufStream;
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::LocalSet;
+use tokio::time::sleep;
 
 const TIME_TO_TOP: Duration = Duration::from_millis(16788);
 const TIME_TO_BOTTOM: Duration = Duration::from_millis(16718);
 const PRESS_TIME: Duration = Duration::from_millis(100);
+// How far the shade can drift during one reaction cycle (a stop pulse plus a
+// poll interval of slop), expressed as a fraction of the given travel time.
+// Used as the window for "close enough" when homing in on a target position.
+// Takes the travel duration as a parameter rather than being a single
+// compile-time constant because `time_to_top`/`time_to_bottom` can differ
+// per shade (configured) or be overwritten by calibration at runtime.
+fn target_tolerance(travel: Duration) -> u16 {
+    if travel.is_zero() {
+        return u16::MAX;
+    }
+    let ticks = (2 * (u16::MAX as u128 * PRESS_TIME.as_micros())) / travel.as_micros();
+    std::cmp::min(ticks, u16::MAX as u128) as u16
+}
+const POSITION_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// How often a background task polls an endstop line for an edge.
+const ENDSTOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How often `subscribe` checks for a state change, and the minimum gap
+// between two pushed frames while the shade keeps moving.
+const SUBSCRIBE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const SUBSCRIBE_THROTTLE: Duration = Duration::from_millis(200);
+// How often the encoder-counting thread polls its input line for an edge.
+const ENCODER_POLL_INTERVAL: Duration = Duration::from_millis(2);
+// How often a task waiting for a shade's in-progress pulse to finish checks
+// back in.
+const PULSE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// gpio_cdev::errors::Result is pub(crate), so we can't name it; this is the
+// same alias against the crate's public `Error` type.
+type GpioResult<T> = std::result::Result<T, gpio_cdev::Error>;
+
+// One daemon can now drive several independent shades; this is the shape of
+// the TOML config file naming them, e.g.:
+//
+//   [[shade]]
+//   name = "living_room"
+//   chip = "/dev/gpiochip0"
+//   line_up = 2
+//   line_down = 3
+//   line_stop = 4
+#[derive(Deserialize)]
+struct Config {
+    shade: Vec<ShadeConfig>,
+}
+
+#[derive(Deserialize)]
+struct ShadeConfig {
+    name: String,
+    chip: String,
+    line_up: u32,
+    line_down: u32,
+    line_stop: u32,
+    line_endstop_top: Option<u32>,
+    line_endstop_bottom: Option<u32>,
+    line_encoder: Option<u32>,
+    time_to_top_ms: Option<u64>,
+    time_to_bottom_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Up,
+    Down,
+}
 
 enum MovementState {
     MovingUp(Instant),
     MovingDown(Instant),
+    MovingToTarget {
+        dir: Direction,
+        target: u16,
+        started: Instant,
+    },
     Stopped,
 }
 
@@ -26,14 +98,31 @@ struct ShadeState {
     movement: MovementState,
     max_pos: u16,
     min_pos: u16,
+    // Full-travel durations used to integrate position from elapsed time.
+    // Start out as guesses and get overwritten with measured values once an
+    // endstop hit (or an explicit `calibrate`) gives us ground truth.
+    time_to_top: Duration,
+    time_to_bottom: Duration,
+    // Optional encoder feedback: a background thread counts rising edges
+    // into this counter as the motor turns. When `counts_per_travel` is
+    // also known (from `calibrate`), position comes from the edge count
+    // instead of integrating elapsed time, and doesn't drift.
+    encoder_counts: Option<Arc<AtomicU64>>,
+    counts_per_travel: Option<u64>,
+    last_counts: u64,
 }
 
 impl Default for ShadeState {
     fn default() -> Self {
         Self {
             movement: MovementState::Stopped,
-            max_pos: u16::max_value(),
+            max_pos: u16::MAX,
             min_pos: 0,
+            time_to_top: TIME_TO_TOP,
+            time_to_bottom: TIME_TO_BOTTOM,
+            encoder_counts: None,
+            counts_per_travel: None,
+            last_counts: 0,
         }
     }
 }
@@ -47,17 +136,65 @@ impl ShadeState {
         self.record();
         self.movement = MovementState::MovingDown(Instant::now());
     }
+    fn move_to_target(&mut self, dir: Direction, target: u16) {
+        self.record();
+        self.movement = MovementState::MovingToTarget {
+            dir,
+            target,
+            started: Instant::now(),
+        };
+    }
     fn stop(&mut self) {
         self.record();
         self.movement = MovementState::Stopped;
     }
+    fn probably(&self) -> u16 {
+        self.max_pos / 2 + self.min_pos / 2
+    }
+    // How many of the 0..=65535 position ticks were covered since the last
+    // call: from the encoder if one is calibrated, otherwise from elapsed
+    // time against the (possibly just-guessed) full-travel duration.
+    fn ticks_moved(&mut self, dur: Duration, travel: Duration) -> u16 {
+        if let (Some(counter), Some(counts_per_travel)) =
+            (&self.encoder_counts, self.counts_per_travel)
+        {
+            let current = counter.load(Ordering::Relaxed);
+            let delta = current.saturating_sub(self.last_counts);
+            self.last_counts = current;
+            if counts_per_travel == 0 {
+                return 0;
+            }
+            let moved = (delta as u128 * u16::MAX as u128) / counts_per_travel as u128;
+            return std::cmp::min(moved, u16::MAX as u128) as u16;
+        }
+        // TODO: div_duration_f32?
+        let moved = (u16::MAX as u128 * dur.as_micros()) / travel.as_micros();
+        std::cmp::min(moved, u16::MAX as u128) as u16
+    }
+    // Called when a top/bottom endstop fires: the measured travel time
+    // replaces the guessed constant, and since we now know exactly where we
+    // are, the min/max uncertainty band collapses to the boundary.
+    fn calibrated(&mut self, dir: Direction, measured: Duration) {
+        match dir {
+            Direction::Up => {
+                self.time_to_top = measured;
+                self.max_pos = 65535;
+                self.min_pos = 65535;
+            }
+            Direction::Down => {
+                self.time_to_bottom = measured;
+                self.max_pos = 0;
+                self.min_pos = 0;
+            }
+        }
+        self.movement = MovementState::Stopped;
+    }
     fn record(&mut self) {
         match self.movement {
             MovementState::MovingUp(t) => {
                 let dur = t.elapsed();
-                // TODO: div_duration_f32?
-                let moved = (u16::max_value() as u128 * dur.as_micros()) / TIME_TO_TOP.as_micros();
-                let moved = std::cmp::min(moved, u16::max_value() as u128) as u16;
+                let travel = self.time_to_top;
+                let moved = self.ticks_moved(dur, travel);
                 self.max_pos = self.max_pos.saturating_add(moved);
                 self.min_pos = self.min_pos.saturating_add(moved);
                 if self.min_pos == 65535 {
@@ -68,10 +205,8 @@ impl ShadeState {
             }
             MovementState::MovingDown(t) => {
                 let dur = t.elapsed();
-                // TODO: div_duration_f32?
-                let moved =
-                    (u16::max_value() as u128 * dur.as_micros()) / TIME_TO_BOTTOM.as_micros();
-                let moved = std::cmp::min(moved, u16::max_value() as u128) as u16;
+                let travel = self.time_to_bottom;
+                let moved = self.ticks_moved(dur, travel);
                 self.max_pos = self.max_pos.saturating_sub(moved);
                 self.min_pos = self.min_pos.saturating_sub(moved);
                 if self.max_pos == 0 {
@@ -80,6 +215,47 @@ impl ShadeState {
                     self.movement = MovementState::MovingDown(Instant::now());
                 }
             }
+            MovementState::MovingToTarget {
+                dir,
+                target,
+                started,
+            } => {
+                let dur = started.elapsed();
+                let travel = match dir {
+                    Direction::Up => self.time_to_top,
+                    Direction::Down => self.time_to_bottom,
+                };
+                let moved = self.ticks_moved(dur, travel);
+                match dir {
+                    Direction::Up => {
+                        self.max_pos = self.max_pos.saturating_add(moved);
+                        self.min_pos = self.min_pos.saturating_add(moved);
+                    }
+                    Direction::Down => {
+                        self.max_pos = self.max_pos.saturating_sub(moved);
+                        self.min_pos = self.min_pos.saturating_sub(moved);
+                    }
+                }
+                let tolerance = target_tolerance(travel);
+                let reached = match dir {
+                    Direction::Up => {
+                        self.min_pos == 65535
+                            || self.probably().saturating_add(tolerance) >= target
+                    }
+                    Direction::Down => {
+                        self.max_pos == 0 || self.probably() <= target.saturating_add(tolerance)
+                    }
+                };
+                if reached {
+                    self.movement = MovementState::Stopped;
+                } else {
+                    self.movement = MovementState::MovingToTarget {
+                        dir,
+                        target,
+                        started: Instant::now(),
+                    };
+                }
+            }
             MovementState::Stopped => (),
         }
     }
@@ -88,6 +264,7 @@ impl ShadeState {
         result.push_str(match self.movement {
             MovementState::MovingUp(_) => "up",
             MovementState::MovingDown(_) => "down",
+            MovementState::MovingToTarget { .. } => "moving_to_target",
             MovementState::Stopped => "stopped",
         });
         result.push_str("\",\"max_pos\":");
@@ -95,7 +272,16 @@ impl ShadeState {
         result.push_str(",\"min_pos\":");
         result.push_str(&self.min_pos.to_string());
         result.push_str(",\"probably\":");
-        result.push_str(&((self.max_pos / 2 + self.min_pos / 2).to_string()));
+        result.push_str(&self.probably().to_string());
+        result.push_str(",\"time_to_top_ms\":");
+        result.push_str(&self.time_to_top.as_millis().to_string());
+        result.push_str(",\"time_to_bottom_ms\":");
+        result.push_str(&self.time_to_bottom.as_millis().to_string());
+        result.push_str(",\"counts_per_travel\":");
+        match self.counts_per_travel {
+            Some(c) => result.push_str(&c.to_string()),
+            None => result.push_str("null"),
+        }
         result.push('}');
         result
     }
@@ -106,65 +292,563 @@ struct ShadeHandle {
     handle_up: LineHandle,
     handle_down: LineHandle,
     handle_stop: LineHandle,
+    // Optional ground-truth endstops; a shade without them just keeps the
+    // time-integrated estimate.
+    endstop_top: Option<Rc<LineHandle>>,
+    endstop_bottom: Option<Rc<LineHandle>>,
+    // Set for the duration of a GPIO pulse (the PRESS_TIME hold between
+    // asserting and releasing a line). Other tasks wanting to pulse the same
+    // shade poll this instead of racing their own line writes in underneath
+    // an in-progress pulse.
+    busy: bool,
 }
 
+// These only cover the synchronous half of a GPIO pulse (raise the line,
+// update the in-memory state) or the tail of one (lower the line again). The
+// PRESS_TIME hold in between is a plain `sleep(...).await` at the call site,
+// with no borrow of the shared `ShadeHandle` held across it, so other tasks
+// on the same LocalSet (other clients' commands, state queries, subscribe
+// pushes) keep running while the line is held high.
 impl ShadeHandle {
-    fn up(&mut self) -> gpio_cdev::errors::Result<()> {
+    fn start_up(&mut self) -> GpioResult<()> {
         self.handle_up.set_value(1)?;
         self.state.move_up();
-        std::thread::sleep(PRESS_TIME);
-        let _ = self.handle_up.set_value(0);
         Ok(())
     }
-    fn down(&mut self) -> gpio_cdev::errors::Result<()> {
+    fn finish_up(&mut self) {
+        let _ = self.handle_up.set_value(0);
+    }
+    fn start_down(&mut self) -> GpioResult<()> {
         self.handle_down.set_value(1)?;
         self.state.move_down();
-        std::thread::sleep(PRESS_TIME);
-        let _ = self.handle_down.set_value(0);
         Ok(())
     }
-    fn stop(&mut self) -> gpio_cdev::errors::Result<()> {
+    fn finish_down(&mut self) {
+        let _ = self.handle_down.set_value(0);
+    }
+    // Returns `false` if the shade was already stopped, in which case no
+    // pulse is needed at all.
+    fn start_stop(&mut self) -> GpioResult<bool> {
         self.state.record();
-        match self.state.movement {
-            MovementState::Stopped => { return Ok(()); }
-            _ => ()
-        };
+        if let MovementState::Stopped = self.state.movement {
+            return Ok(false);
+        }
         self.handle_stop.set_value(1)?;
         self.state.stop();
-        std::thread::sleep(PRESS_TIME);
+        Ok(true)
+    }
+    fn finish_stop(&mut self) {
         let _ = self.handle_stop.set_value(0);
-        Ok(())
     }
+    fn start_move_to(&mut self, target: u16) -> GpioResult<Direction> {
+        self.state.record();
+        let dir = if target >= self.state.probably() {
+            Direction::Up
+        } else {
+            Direction::Down
+        };
+        match dir {
+            Direction::Up => self.handle_up.set_value(1)?,
+            Direction::Down => self.handle_down.set_value(1)?,
+        };
+        self.state.move_to_target(dir, target);
+        Ok(dir)
+    }
+    fn finish_move_to(&mut self, dir: Direction) {
+        let _ = match dir {
+            Direction::Up => self.handle_up.set_value(0),
+            Direction::Down => self.handle_down.set_value(0),
+        };
+    }
+    fn endstop(&self, dir: Direction) -> Option<Rc<LineHandle>> {
+        match dir {
+            Direction::Up => self.endstop_top.clone(),
+            Direction::Down => self.endstop_bottom.clone(),
+        }
+    }
+    // Advances the estimate. Unlike the min/max-pos boundaries, a target
+    // isn't a mechanical limit, so on `Reached` the caller still has to
+    // press the stop line itself.
+    fn advance_toward_target(&mut self) -> TargetPoll {
+        if !matches!(self.state.movement, MovementState::MovingToTarget { .. }) {
+            return TargetPoll::Cancelled;
+        }
+        self.state.record();
+        if matches!(self.state.movement, MovementState::MovingToTarget { .. }) {
+            TargetPoll::StillMoving
+        } else {
+            TargetPoll::Reached
+        }
+    }
+}
+
+enum TargetPoll {
+    StillMoving,
+    Reached,
+    Cancelled,
+}
+
+// Waits for any in-progress pulse on this shade to finish, then claims
+// `busy` for the caller's own pulse. Without this, e.g. an `up` and a `down`
+// arriving on different connections could each assert their line while the
+// other's PRESS_TIME sleep is in progress, holding up and down simultaneously.
+async fn acquire_pulse(shade_handle: &Rc<RefCell<ShadeHandle>>) {
+    loop {
+        if !shade_handle.borrow().busy {
+            shade_handle.borrow_mut().busy = true;
+            return;
+        }
+        sleep(PULSE_POLL_INTERVAL).await;
+    }
+}
+
+fn release_pulse(shade_handle: &Rc<RefCell<ShadeHandle>>) {
+    shade_handle.borrow_mut().busy = false;
+}
+
+async fn up(shade_handle: &Rc<RefCell<ShadeHandle>>) -> GpioResult<()> {
+    acquire_pulse(shade_handle).await;
+    let result = shade_handle.borrow_mut().start_up();
+    if result.is_err() {
+        release_pulse(shade_handle);
+        return result;
+    }
+    sleep(PRESS_TIME).await;
+    shade_handle.borrow_mut().finish_up();
+    release_pulse(shade_handle);
+    Ok(())
+}
+
+async fn down(shade_handle: &Rc<RefCell<ShadeHandle>>) -> GpioResult<()> {
+    acquire_pulse(shade_handle).await;
+    let result = shade_handle.borrow_mut().start_down();
+    if result.is_err() {
+        release_pulse(shade_handle);
+        return result;
+    }
+    sleep(PRESS_TIME).await;
+    shade_handle.borrow_mut().finish_down();
+    release_pulse(shade_handle);
+    Ok(())
+}
+
+async fn stop(shade_handle: &Rc<RefCell<ShadeHandle>>) -> GpioResult<()> {
+    acquire_pulse(shade_handle).await;
+    let needs_pulse = match shade_handle.borrow_mut().start_stop() {
+        Ok(needs_pulse) => needs_pulse,
+        Err(e) => {
+            release_pulse(shade_handle);
+            return Err(e);
+        }
+    };
+    if !needs_pulse {
+        release_pulse(shade_handle);
+        return Ok(());
+    }
+    sleep(PRESS_TIME).await;
+    shade_handle.borrow_mut().finish_stop();
+    release_pulse(shade_handle);
+    Ok(())
+}
+
+async fn move_to(shade_handle: &Rc<RefCell<ShadeHandle>>, target: u16) -> GpioResult<Direction> {
+    acquire_pulse(shade_handle).await;
+    let dir = match shade_handle.borrow_mut().start_move_to(target) {
+        Ok(dir) => dir,
+        Err(e) => {
+            release_pulse(shade_handle);
+            return Err(e);
+        }
+    };
+    sleep(PRESS_TIME).await;
+    shade_handle.borrow_mut().finish_move_to(dir);
+    release_pulse(shade_handle);
+    Ok(dir)
+}
+
+// Polled by `monitor_position` while homing in on a `position` target.
+// Returns `true` if the caller should keep polling.
+async fn poll_toward_target(shade_handle: &Rc<RefCell<ShadeHandle>>) -> bool {
+    let poll = shade_handle.borrow_mut().advance_toward_target();
+    match poll {
+        TargetPoll::StillMoving => true,
+        TargetPoll::Cancelled => false,
+        TargetPoll::Reached => {
+            acquire_pulse(shade_handle).await;
+            let _ = shade_handle.borrow_mut().handle_stop.set_value(1);
+            sleep(PRESS_TIME).await;
+            shade_handle.borrow_mut().finish_stop();
+            release_pulse(shade_handle);
+            false
+        }
+    }
+}
+
+type Shades = Rc<HashMap<String, Rc<RefCell<ShadeHandle>>>>;
+
+// Picks the shade a command line addresses: `<name> <command>` if the first
+// word names a configured shade, or the sole shade if there's only one
+// (keeping the old bare-command protocol working for single-shade configs).
+fn resolve_shade<'a>(shades: &Shades, line: &'a str) -> Option<(Rc<RefCell<ShadeHandle>>, &'a str)> {
+    if let Some((name, rest)) = line.split_once(' ') {
+        if let Some(handle) = shades.get(name) {
+            return Some((handle.clone(), rest));
+        }
+    }
+    if shades.len() == 1 {
+        return shades.values().next().map(|handle| (handle.clone(), line));
+    }
+    None
+}
+
+fn list_json(shades: &Shades) -> String {
+    let mut result = String::from("[");
+    let mut first = true;
+    for (name, handle) in shades.iter() {
+        if !first {
+            result.push(',');
+        }
+        first = false;
+        let state_json = handle.borrow().state.json();
+        result.push_str("{\"name\":\"");
+        result.push_str(name);
+        result.push_str("\",");
+        // state_json is `{"state":...}`; splice the name field in place of
+        // its leading brace instead of building the object twice.
+        result.push_str(&state_json[1..]);
+    }
+    result.push(']');
+    result
 }
 
-fn handle_client(tcp_stream: TcpStream, shade_handle: Arc<Mutex<ShadeHandle>>) {
-    if let Ok(peer_addr) = tcp_stream.peer_addr() {
-        let mut stream = BufStream::new(tcp_stream);
-        println!("Connected: {}", peer_addr);
-        let mut line = String::new();
-        while let Ok(read_bytes) = stream.read_line(&mut line) {
-            if read_bytes == 0 {
-                println!("Disconnected: {}", peer_addr);
+async fn run_command(shade_handle: &Rc<RefCell<ShadeHandle>>, cmd: &str) {
+    if cmd == "up" {
+        up(shade_handle).await.unwrap();
+        start_watching_endstop(shade_handle.clone(), Direction::Up);
+    } else if cmd == "down" {
+        down(shade_handle).await.unwrap();
+        start_watching_endstop(shade_handle.clone(), Direction::Down);
+    } else if cmd == "stop" {
+        stop(shade_handle).await.unwrap();
+    } else if cmd == "calibrate" {
+        calibrate(shade_handle.clone()).await;
+    } else if let Some(target) = cmd
+        .strip_prefix("position ")
+        .and_then(|rest| rest.trim().parse::<u16>().ok())
+    {
+        let dir = move_to(shade_handle, target).await.unwrap();
+        start_watching_endstop(shade_handle.clone(), dir);
+        tokio::task::spawn_local(monitor_position(shade_handle.clone()));
+    } else {
+        shade_handle.borrow_mut().state.record();
+    }
+}
+
+async fn handle_client(tcp_stream: TcpStream, shades: Shades) {
+    let peer_addr = match tcp_stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    let (read_half, mut write_half) = tcp_stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    println!("Connected: {}", peer_addr);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read_bytes = match reader.read_line(&mut line).await {
+            Ok(n) => n,
+            Err(_) => break,
+        };
+        if read_bytes == 0 {
+            println!("Disconnected: {}", peer_addr);
+            break;
+        }
+        let cmd_line = line.trim_end();
+        if cmd_line == "list" {
+            let json = list_json(&shades);
+            if write_half.write_all(json.as_bytes()).await.is_err() {
                 break;
             }
-            if line == "up\n" {
-                shade_handle.lock().unwrap().up().unwrap();
-            } else if line == "down\n" {
-                shade_handle.lock().unwrap().down().unwrap();
-            } else if line == "stop\n" {
-                shade_handle.lock().unwrap().stop().unwrap();
-            } else {
-                shade_handle.lock().unwrap().state.record();
+            continue;
+        }
+        let (shade_handle, cmd) = match resolve_shade(&shades, cmd_line) {
+            Some(found) => found,
+            None => {
+                if write_half
+                    .write_all(b"{\"error\":\"unknown shade\"}")
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+        if cmd == "subscribe" {
+            // Hands the write half off to a dedicated pusher and stops
+            // replying per-command on this connection; it's now a live feed.
+            tokio::task::spawn_local(subscribe(shade_handle, write_half));
+            return;
+        }
+        run_command(&shade_handle, cmd).await;
+        let json = shade_handle.borrow().state.json();
+        if write_half.write_all(json.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Pushed-state feed for a `subscribe`d connection: checks in at
+// SUBSCRIBE_POLL_INTERVAL, but only actually writes a frame at most once per
+// SUBSCRIBE_THROTTLE while the shade is moving, coalescing the updates in
+// between. Emits exactly one frame right after movement stops regardless of
+// the throttle, then goes quiet until the shade moves again.
+async fn subscribe(shade_handle: Rc<RefCell<ShadeHandle>>, mut write_half: OwnedWriteHalf) {
+    let mut last_emit: Option<Instant> = None;
+    let mut was_moving = false;
+    loop {
+        sleep(SUBSCRIBE_POLL_INTERVAL).await;
+        let (mut json, moving) = {
+            let mut handle = shade_handle.borrow_mut();
+            handle.state.record();
+            let moving = !matches!(handle.state.movement, MovementState::Stopped);
+            (handle.state.json(), moving)
+        };
+        let should_emit = if moving {
+            last_emit.is_none_or(|t| t.elapsed() >= SUBSCRIBE_THROTTLE)
+        } else {
+            was_moving
+        };
+        if should_emit {
+            // Frames are pushed back-to-back with no request to pace them,
+            // unlike the request/reply side of the protocol; a newline lets
+            // the client tell consecutive frames apart with `read_line`.
+            json.push('\n');
+            if write_half.write_all(json.as_bytes()).await.is_err() {
+                return;
+            }
+            last_emit = Some(Instant::now());
+        }
+        was_moving = moving;
+    }
+}
+
+async fn monitor_position(shade_handle: Rc<RefCell<ShadeHandle>>) {
+    loop {
+        sleep(POSITION_POLL_INTERVAL).await;
+        if !poll_toward_target(&shade_handle).await {
+            break;
+        }
+    }
+}
+
+// Starts a background watcher for the endstop in `dir`, if one is configured.
+// No-op otherwise, so callers can fire this after every `up`/`down`/`move_to`
+// without checking for hardware support themselves.
+fn start_watching_endstop(shade_handle: Rc<RefCell<ShadeHandle>>, dir: Direction) {
+    let line = shade_handle.borrow().endstop(dir);
+    if let Some(line) = line {
+        tokio::task::spawn_local(watch_endstop(shade_handle, dir, line));
+    }
+}
+
+// Measures travel time for `calibrate`/endstop auto-stop. Reuses the Instant
+// ShadeState stamped when the motor actually started moving rather than one
+// taken here: start_watching_endstop is only spawned after `up`/`down`
+// return, which already includes the full PRESS_TIME sleep, so a fresh
+// Instant::now() would under-report every measured travel time by ~100ms.
+async fn watch_endstop(shade_handle: Rc<RefCell<ShadeHandle>>, dir: Direction, line: Rc<LineHandle>) {
+    let started = match shade_handle.borrow().state.movement {
+        MovementState::MovingUp(t) if dir == Direction::Up => t,
+        MovementState::MovingDown(t) if dir == Direction::Down => t,
+        MovementState::MovingToTarget { dir: moving, started, .. } if moving == dir => started,
+        _ => Instant::now(),
+    };
+    loop {
+        sleep(ENDSTOP_POLL_INTERVAL).await;
+        {
+            let handle = shade_handle.borrow();
+            let still_moving = match handle.state.movement {
+                MovementState::MovingUp(_) => dir == Direction::Up,
+                MovementState::MovingDown(_) => dir == Direction::Down,
+                MovementState::MovingToTarget { dir: moving, .. } => moving == dir,
+                MovementState::Stopped => false,
+            };
+            if !still_moving {
+                return;
+            }
+        }
+        if line.get_value().unwrap_or(0) != 0 {
+            let measured = started.elapsed();
+            acquire_pulse(&shade_handle).await;
+            {
+                let mut handle = shade_handle.borrow_mut();
+                let _ = handle.handle_stop.set_value(1);
+                handle.state.calibrated(dir, measured);
             }
-            stream.write(shade_handle.lock().unwrap().state.json().as_bytes()).unwrap();
+            sleep(PRESS_TIME).await;
+            let _ = shade_handle.borrow().handle_stop.set_value(0);
+            release_pulse(&shade_handle);
+            return;
+        }
+    }
+}
+
+// Reads the current edge count, if an encoder is configured.
+fn current_counts(shade_handle: &Rc<RefCell<ShadeHandle>>) -> Option<u64> {
+    shade_handle
+        .borrow()
+        .state
+        .encoder_counts
+        .as_ref()
+        .map(|c| c.load(Ordering::Relaxed))
+}
+
+// Drives the shade fully up then fully down to measure real travel times,
+// relying on the endstop watchers to call `ShadeState::calibrated` and
+// collapse the uncertainty band. The measured times end up in `time_to_top`/
+// `time_to_bottom`, which the caller's usual state.json() reply includes.
+// If an encoder is configured, the edge counts for the up and down legs are
+// averaged into `counts_per_travel` at the same time.
+async fn calibrate(shade_handle: Rc<RefCell<ShadeHandle>>) {
+    let start = current_counts(&shade_handle);
+    up(&shade_handle).await.unwrap();
+    start_watching_endstop(shade_handle.clone(), Direction::Up);
+    wait_until_stopped(&shade_handle).await;
+    let mid = current_counts(&shade_handle);
+    down(&shade_handle).await.unwrap();
+    start_watching_endstop(shade_handle.clone(), Direction::Down);
+    wait_until_stopped(&shade_handle).await;
+    let end = current_counts(&shade_handle);
+    if let (Some(start), Some(mid), Some(end)) = (start, mid, end) {
+        let up_delta = mid.saturating_sub(start);
+        let down_delta = end.saturating_sub(mid);
+        if up_delta > 0 && down_delta > 0 {
+            let mut handle = shade_handle.borrow_mut();
+            handle.state.counts_per_travel = Some((up_delta + down_delta) / 2);
+            // ticks_moved only updates last_counts once encoder mode is live
+            // (i.e. once counts_per_travel is set), so it's still sitting at
+            // its Default of 0 here even though the counter kept counting
+            // the whole time this function ran. Baseline it to the current
+            // count now, or the very next movement sees the entire
+            // calibration run as one giant, saturating delta.
+            handle.state.last_counts = end;
         }
     }
 }
 
+async fn wait_until_stopped(shade_handle: &Rc<RefCell<ShadeHandle>>) {
+    loop {
+        sleep(POSITION_POLL_INTERVAL).await;
+        let mut handle = shade_handle.borrow_mut();
+        handle.state.record();
+        if let MovementState::Stopped = handle.state.movement {
+            break;
+        }
+    }
+}
+
+// Counts rising edges on an encoder input line into a shared counter. This
+// runs on a real OS thread rather than as an async task: it's cheap, it's
+// the only thing touching `line`, and publishing into an `AtomicU64` needs
+// no coordination with the single-threaded executor's `Rc<RefCell<_>>` state.
+fn spawn_encoder_counter(line: LineHandle) -> Arc<AtomicU64> {
+    let counts = Arc::new(AtomicU64::new(0));
+    let counter = counts.clone();
+    std::thread::spawn(move || {
+        let mut last = line.get_value().unwrap_or(0);
+        loop {
+            std::thread::sleep(ENCODER_POLL_INTERVAL);
+            let value = match line.get_value() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if last == 0 && value == 1 {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            last = value;
+        }
+    });
+    counts
+}
+
+// Opens one shade's GPIO hardware per its config entry. Endstops and the
+// encoder are optional calibration hardware, same as the single-shade
+// defaults this replaces: if a line isn't configured (or isn't wired up),
+// that shade just keeps the time-integrated estimate.
+fn build_shade(cfg: &ShadeConfig) -> Result<ShadeHandle, Error> {
+    let mut chip = Chip::new(&cfg.chip)?;
+    let flags = LineRequestFlags::OUTPUT | LineRequestFlags::ACTIVE_LOW;
+    let endstop_top = cfg
+        .line_endstop_top
+        .and_then(|offset| {
+            chip.get_line(offset)
+                .and_then(|l| l.request(LineRequestFlags::INPUT, 0, "Shade top endstop"))
+                .ok()
+        })
+        .map(Rc::new);
+    let endstop_bottom = cfg
+        .line_endstop_bottom
+        .and_then(|offset| {
+            chip.get_line(offset)
+                .and_then(|l| l.request(LineRequestFlags::INPUT, 0, "Shade bottom endstop"))
+                .ok()
+        })
+        .map(Rc::new);
+    let encoder_counts = cfg
+        .line_encoder
+        .and_then(|offset| {
+            chip.get_line(offset)
+                .and_then(|l| l.request(LineRequestFlags::INPUT, 0, "Shade encoder"))
+                .ok()
+        })
+        .map(spawn_encoder_counter);
+
+    let mut state = ShadeState::default();
+    if let Some(ms) = cfg.time_to_top_ms {
+        if ms == 0 {
+            return Err(Error::InvalidConfig(format!(
+                "{}: time_to_top_ms must not be 0",
+                cfg.name
+            )));
+        }
+        state.time_to_top = Duration::from_millis(ms);
+    }
+    if let Some(ms) = cfg.time_to_bottom_ms {
+        if ms == 0 {
+            return Err(Error::InvalidConfig(format!(
+                "{}: time_to_bottom_ms must not be 0",
+                cfg.name
+            )));
+        }
+        state.time_to_bottom = Duration::from_millis(ms);
+    }
+    state.encoder_counts = encoder_counts;
+
+    Ok(ShadeHandle {
+        state,
+        handle_up: chip.get_line(cfg.line_up)?.request(flags, 0, "Shades up")?,
+        handle_down: chip
+            .get_line(cfg.line_down)?
+            .request(flags, 0, "Shades down")?,
+        handle_stop: chip
+            .get_line(cfg.line_stop)?
+            .request(flags, 0, "Shades stop")?,
+        endstop_top,
+        endstop_bottom,
+        busy: false,
+    })
+}
+
+// The variant payloads are only ever read by `main`'s top-level Debug print
+// on exit, which the dead_code lint doesn't credit as a use.
 #[derive(Debug)]
+#[allow(dead_code)]
 enum Error {
     Io(io::Error),
     Gpio(gpio_cdev::errors::Error),
+    Config(toml::de::Error),
+    InvalidConfig(String),
 }
 
 impl From<io::Error> for Error {
@@ -179,30 +863,50 @@ impl From<gpio_cdev::errors::Error> for Error {
     }
 }
 
-fn main() -> Result<(), Error> {
-    let mut chip = Chip::new("/dev/gpiochip0")?;
-    println!("Opened GPIO");
-    let flags = LineRequestFlags::OUTPUT | LineRequestFlags::ACTIVE_LOW;
-    let shade_handle = ShadeHandle {
-        state: ShadeState::default(),
-        handle_down: chip.get_line(3)?.request(flags, 0, "Shades down")?,
-        handle_up: chip.get_line(2)?.request(flags, 0, "Shades up")?,
-        handle_stop: chip.get_line(4)?.request(flags, 0, "Shades stop")?,
-    };
-    println!("Opened lines");
-    let shade_handle = Arc::new(Mutex::new(shade_handle));
-    let listener = TcpListener::bind("[::]:9911")?;
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error {
+        Error::Config(e)
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let config_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "shaded.toml".to_string());
+    let config_text = std::fs::read_to_string(&config_path)?;
+    let config: Config = toml::from_str(&config_text)?;
+
+    let mut shades = HashMap::new();
+    for shade_cfg in &config.shade {
+        let handle = build_shade(shade_cfg)?;
+        println!("Opened {}", shade_cfg.name);
+        shades.insert(shade_cfg.name.clone(), Rc::new(RefCell::new(handle)));
+    }
+    let shades: Shades = Rc::new(shades);
+
+    let listener = TcpListener::bind("[::]:9911").await?;
     println!("Listening on :9911");
-    for stream in listener.incoming() {
-        match stream {
-            Err(e) => println!("accept: {}", e),
-            Ok(stream) => {
-                let shade_handle = shade_handle.clone();
-                thread::spawn(move || {
-                    handle_client(stream, shade_handle);
-                });
+
+    // gpio_cdev's LineHandle isn't Send, so the whole server runs on this one
+    // task/thread: every connection is just another task spawned onto the
+    // same LocalSet instead of getting its own OS thread.
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            loop {
+                match listener.accept().await {
+                    Err(e) => println!("accept: {}", e),
+                    Ok((stream, _)) => {
+                        // Nagle's algorithm would otherwise add latency to
+                        // these small framed command/state messages.
+                        let _ = stream.set_nodelay(true);
+                        let shades = shades.clone();
+                        tokio::task::spawn_local(handle_client(stream, shades));
+                    }
+                }
             }
-        };
-    }
+        })
+        .await;
     Ok(())
 }